@@ -1,35 +1,52 @@
 use std::{
     collections::{HashMap, HashSet},
-    fmt::Debug,
+    fs,
     future::Future,
-    path::Path,
-    sync::Arc,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
 use futures::StreamExt;
 use parking_lot::RwLock;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use taskinator_communicator::game::{MeetingState, Player, State};
-use tokio::{signal::ctrl_c, sync::watch::Receiver, time::sleep};
+use tokio::{
+    signal::ctrl_c,
+    sync::{watch::Receiver, Mutex},
+    task,
+    time::{sleep, timeout},
+};
 use twilight_cache_inmemory::{model::CachedMember, InMemoryCache, ResourceType};
-use twilight_command_parser::{Arguments, Command, CommandParserConfig, Parser};
+use twilight_command_parser::{Command, CommandParserConfig, Parser};
 use twilight_embed_builder::{EmbedBuilder, EmbedFieldBuilder};
 use twilight_gateway::{shard::Events, Event, EventTypeFlags, Intents, Shard};
 use twilight_http::{error::Error as HttpError, Client};
 use twilight_mention::{Mention, ParseMention};
 use twilight_model::{
-    channel::{Channel, GuildChannel, Message},
-    id::{ChannelId, UserId},
+    application::interaction::{ApplicationCommand, Interaction},
+    channel::{
+        permission_overwrite::{PermissionOverwrite, PermissionOverwriteType},
+        Channel, GuildChannel, Message, TextChannel, VoiceChannel,
+    },
+    guild::Permissions,
+    id::{ChannelId, GuildId, RoleId, UserId},
 };
 
 use crate::{
+    commands::{deferred_response, string_option, user_option, BotCommand, Response},
     config::Config,
-    utils::{KnownAs, ReplyTo},
+    utils::{join_partitioned, write_atomically, KnownAs, ReplyTo},
     Result,
 };
 
 type TwiResult<T> = std::result::Result<T, HttpError>;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum BotState {
     PreGame,
     InGame,
@@ -37,20 +54,62 @@ enum BotState {
     GameOver,
 }
 
+/// The outcome of checking the bot's permissions before moderating a game
+#[derive(Clone, Copy)]
+enum Moderation {
+    Allowed,
+    /// The missing permissions have been reported to the broadcast channel
+    Refused,
+    /// The guild has not been cached yet, so the check should be tried again shortly
+    NotReady,
+}
+
+/// The game state as reported by the Among Us process, as far as voice moderation is concerned
+#[derive(Clone, Copy)]
+enum Phase {
+    NoGame,
+    Gameplay,
+    Meeting,
+}
+
+/// What to do to the players in voice after a change of game state
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    StartGame,
+    StartMeeting,
+    /// Also decides whether the game is over, so may change the bot state again
+    EndMeeting,
+    EndGame,
+}
+
+/// Granted to dead players in dead chat
+const DEAD_CHAT_PERMISSIONS: Permissions = Permissions::from_bits_truncate(
+    Permissions::VIEW_CHANNEL.bits()
+        | Permissions::SEND_MESSAGES.bits()
+        | Permissions::READ_MESSAGE_HISTORY.bits(),
+);
+
 pub struct Builder {
     cache: InMemoryCache,
+    token: String,
     discord_gateway: Shard,
     gateway_events: Events,
     discord_client: Client,
     command_parser: Arc<Parser<'static>>,
+    command_names: Arc<HashMap<String, BotCommand>>,
+    prefix: Arc<String>,
+    text_commands: bool,
     broadcast_channel: ChannelId,
     living_channel: ChannelId,
     dead_channel: ChannelId,
+    dead_chat: Option<ChannelId>,
+    dead_chat_file: PathBuf,
+    names_file: PathBuf,
 }
 
 impl Builder {
     pub async fn build(self, game_state_rx: Receiver<Option<State>>) -> Result<(Bot, Events)> {
-        let (owners, bot_id) = {
+        let owners = {
             let mut owners = HashSet::new();
 
             let app_info = self.discord_client.current_user_application().await?;
@@ -59,9 +118,13 @@ impl Builder {
             } else {
                 owners.insert(app_info.owner.id);
             }
-            (Arc::new(owners), UserId(app_info.id.0))
+            self.discord_client.set_application_id(app_info.id);
+            Arc::new(owners)
         };
 
+        // Older applications have a different ID to their bot user
+        let bot_id = self.discord_client.current_user().await?.id;
+
         // Validate channels
         let broadcast_channel = if let Channel::Guild(channel) = self
             .discord_client
@@ -97,6 +160,15 @@ impl Builder {
             panic!();
         };
 
+        // Slash commands are registered to the guild as these update immediately, unlike global
+        // commands
+        if let Some(guild_id) = living_channel.guild_id {
+            if let Err(why) = self.register_slash_commands(guild_id).await {
+                tracing::error!("Failed to register slash commands");
+                tracing::error!("{}", why);
+            }
+        }
+
         let dead_channel = if let Channel::Guild(channel) = self
             .discord_client
             .channel(self.dead_channel)
@@ -114,37 +186,89 @@ impl Builder {
             panic!();
         };
 
+        let dead_chat = match self.dead_chat {
+            Some(dead_chat) => {
+                if let Some(Channel::Guild(GuildChannel::Text(tc))) =
+                    self.discord_client.channel(dead_chat).await?
+                {
+                    Some(tc.id)
+                } else {
+                    tracing::error!("Dead chat must be a text channel in a guild");
+                    panic!();
+                }
+            }
+            None => None,
+        };
+
         Ok((
             Bot {
                 cache: self.cache,
-                discord_gateway: self.discord_gateway,
+                token: Arc::new(self.token),
+                discord_gateway: Arc::new(RwLock::new(self.discord_gateway)),
+                shutting_down: Arc::new(AtomicBool::new(false)),
                 discord_client: self.discord_client,
                 command_parser: self.command_parser,
+                command_names: self.command_names,
+                prefix: self.prefix,
+                text_commands: self.text_commands,
                 bot_id,
                 owners,
                 broadcast_channel: broadcast_channel.id,
                 living_channel: living_channel.id,
                 dead_channel: dead_channel.id,
-                player_names: Arc::new(RwLock::new(HashMap::new())),
+                dead_chat,
+                dead_chat_members: Arc::new(RwLock::new(
+                    load_toml::<DeadChatMembers>(&self.dead_chat_file, "dead chat members")
+                        .members
+                        .into_iter()
+                        .filter_map(|uid| uid.parse().ok().map(UserId))
+                        .collect(),
+                )),
+                dead_chat_file: Arc::new(self.dead_chat_file),
+                dead_chat_save: Arc::new(Mutex::new(())),
+                player_names: Arc::new(RwLock::new(load_player_names(&self.names_file))),
+                names_file: Arc::new(self.names_file),
+                names_save: Arc::new(Mutex::new(())),
                 game_state_rx,
             },
             self.gateway_events,
         ))
     }
+
+    async fn register_slash_commands(&self, guild_id: GuildId) -> Result<()> {
+        self.discord_client
+            .set_guild_commands(guild_id, BotCommand::slash_commands())?
+            .await?;
+
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
 pub struct Bot {
     cache: InMemoryCache,
-    discord_gateway: Shard,
+    token: Arc<String>,
+    discord_gateway: Arc<RwLock<Shard>>,
+    shutting_down: Arc<AtomicBool>,
     discord_client: Client,
     command_parser: Arc<Parser<'static>>,
+    command_names: Arc<HashMap<String, BotCommand>>,
+    prefix: Arc<String>,
+    text_commands: bool,
     bot_id: UserId,
     owners: Arc<HashSet<UserId>>,
     broadcast_channel: ChannelId,
     living_channel: ChannelId,
     dead_channel: ChannelId,
+    dead_chat: Option<ChannelId>,
+    /// Members who have been granted access to dead chat during the current game
+    dead_chat_members: Arc<RwLock<HashSet<UserId>>>,
+    dead_chat_file: Arc<PathBuf>,
+    dead_chat_save: Arc<Mutex<()>>,
     player_names: Arc<RwLock<HashMap<UserId, String>>>,
+    names_file: Arc<PathBuf>,
+    /// Held whilst saving in game names so that saves cannot complete out of order
+    names_save: Arc<Mutex<()>>,
     game_state_rx: Receiver<Option<State>>,
 }
 
@@ -161,21 +285,7 @@ impl Bot {
 
         let discord_client = Client::new(&config.token);
 
-        let event_flags: EventTypeFlags = EventTypeFlags::GUILD_CREATE
-            | EventTypeFlags::MEMBER_ADD
-            | EventTypeFlags::MEMBER_UPDATE
-            | EventTypeFlags::MESSAGE_CREATE
-            | EventTypeFlags::VOICE_STATE_UPDATE;
-
-        let (discord_gateway, gateway_events) = Shard::builder(
-            &config.token,
-            Intents::GUILDS
-                | Intents::GUILD_MEMBERS
-                | Intents::GUILD_MESSAGES
-                | Intents::GUILD_VOICE_STATES,
-        )
-        .event_types(event_flags)
-        .build();
+        let (discord_gateway, gateway_events) = build_shard(&config.token);
 
         let (broadcast_channel, living_channel, dead_channel) = (
             config.broadcast_channel,
@@ -188,148 +298,441 @@ impl Bot {
                 ResourceType::CHANNEL
                     | ResourceType::GUILD
                     | ResourceType::MEMBER
+                    | ResourceType::ROLE
                     | ResourceType::USER
                     | ResourceType::VOICE_STATE,
             )
             .build();
 
+        if let Some(unknown) = config
+            .commands
+            .keys()
+            .find(|key| !BotCommand::ALL.iter().any(|c| c.key() == key.as_str()))
+        {
+            tracing::error!("Unknown command in config: {}", unknown);
+            panic!();
+        }
+
+        let command_names = BotCommand::ALL
+            .iter()
+            .map(|&command| {
+                let name = config
+                    .commands
+                    .get(command.key())
+                    .cloned()
+                    .unwrap_or_else(|| command.key().to_owned());
+                (name, command)
+            })
+            .collect::<HashMap<_, _>>();
+
+        if command_names.len() < BotCommand::ALL.len() {
+            tracing::error!("Each command must be given a unique name");
+            panic!();
+        }
+
+        // Only the first prefix is shown in help
+        let prefix = config.prefixes[0].clone();
+
         let command_parser = {
             let mut parser_config = CommandParserConfig::new();
-            parser_config.add_prefix("~");
-            parser_config.add_command("ident", false);
-            parser_config.add_command("check", false);
-            parser_config.add_command("stop", false);
+            for prefix in config.prefixes {
+                parser_config.add_prefix(prefix);
+            }
+            for name in command_names.keys() {
+                parser_config.add_command(name.clone(), false);
+            }
 
             Arc::new(Parser::new(parser_config))
         };
 
         Builder {
             cache,
+            token: config.token,
             discord_gateway,
             gateway_events,
             discord_client,
             command_parser,
+            command_names: Arc::new(command_names),
+            prefix: Arc::new(prefix),
+            text_commands: config.text_commands,
             broadcast_channel,
             living_channel,
             dead_channel,
+            dead_chat: config.dead_chat,
+            dead_chat_file: config.dead_chat_file,
+            names_file: config.names_file,
         }
     }
 
-    pub async fn start(&mut self, mut events: Events) -> Result<()> {
-        let shutdown_handle = self.discord_gateway.clone();
+    pub async fn start(&self, mut events: Events) -> Result<()> {
+        let bot = self.clone();
 
         tokio::spawn(async move {
-            if let Err(why) = ctrl_c().await {
-                tracing::error!("There was an error registering the ctrl+c handler");
-                tracing::error!("{}", why);
-            }
+            shutdown_signal().await;
 
-            shutdown_handle.shutdown();
+            bot.shutdown();
         });
 
-        self.discord_gateway.start().await?;
+        let mut shard = self.discord_gateway.read().clone();
+        shard.start().await?;
 
         let mut bot = self.clone();
         tokio::spawn(async move {
             let mut bot_state = BotState::PreGame;
+            let mut cache_ready = true;
             loop {
-                if let Err(why) = bot.game_state_rx.changed().await {
+                // Whilst waiting for the cache, check again even if the game state has not changed
+                let changed = if cache_ready {
+                    bot.game_state_rx.changed().await
+                } else {
+                    timeout(Duration::from_secs(2), bot.game_state_rx.changed())
+                        .await
+                        .unwrap_or(Ok(()))
+                };
+                if let Err(why) = changed {
                     tracing::error!("Game state receive failed: {}", why);
                     break;
                 }
-                let state = bot.game_state_rx.borrow().as_ref().map(|s| (*s).clone());
-                match state {
-                    Some(State::InGame { meeting, .. })
-                        if matches!(
-                            meeting,
-                            MeetingState::Discussion
-                                | MeetingState::NotVoted
-                                | MeetingState::Voted
-                                | MeetingState::Results
-                        ) =>
-                    {
-                        // In a meeting
-                        if matches!(bot_state, BotState::PreGame | BotState::InGame) {
-                            bot_state = BotState::InMeeting;
-                            bot.start_meeting().await;
-                        }
+
+                let phase = match &*bot.game_state_rx.borrow() {
+                    Some(State::InGame { meeting, .. }) if is_meeting(meeting) => Phase::Meeting,
+                    Some(State::InGame { .. }) => Phase::Gameplay,
+                    Some(State::Lobby { .. } | State::Menu) | None => Phase::NoGame,
+                };
+
+                let moderation = if needs_moderation_check(bot_state, phase) {
+                    bot.can_moderate().await
+                } else {
+                    Moderation::Allowed
+                };
+                cache_ready = !matches!(moderation, Moderation::NotReady);
+
+                let (next_state, action) = transition(bot_state, phase, moderation);
+                bot_state = next_state;
+
+                match action {
+                    Some(Action::StartGame) => bot.start_game().await,
+                    Some(Action::StartMeeting) => {
+                        bot.start_meeting().await;
                     }
-                    Some(State::InGame { .. }) => {
-                        // In gameplay
-                        match bot_state {
-                            BotState::InMeeting => {
-                                bot.end_meeting(&mut bot_state).await;
-                            }
-                            BotState::PreGame => {
-                                bot_state = BotState::InGame;
-                                bot.start_game().await;
-                            }
-                            _ => {}
+                    Some(Action::EndMeeting) => bot.end_meeting(&mut bot_state).await,
+                    Some(Action::EndGame) => bot.end_game().await,
+                    None => {}
+                }
+
+                if matches!(phase, Phase::NoGame) {
+                    // Clears any access left over from a game interrupted by a restart
+                    bot.revoke_dead_chat().await;
+                }
+            }
+        });
+
+        loop {
+            while let Some(event) = events.next().await {
+                self.cache.update(&event);
+
+                match event {
+                    Event::MessageCreate(message) if self.text_commands && !message.author.bot => {
+                        if let Err(why) = self.handle_command(&message).await {
+                            tracing::error!("An error occurred whilst processing a command!");
+                            tracing::error!("Message: {:?}", &message);
+                            tracing::error!("Error: {}", why);
                         }
                     }
-                    Some(State::Lobby { .. } | State::Menu) | None => {
-                        // No game running or crash
-                        if matches!(bot_state, BotState::InGame | BotState::InMeeting) {
-                            bot_state = BotState::PreGame;
-                            bot.end_game().await;
-                        }
-
-                        if matches!(bot_state, BotState::GameOver) {
-                            bot_state = BotState::PreGame;
+                    Event::InteractionCreate(interaction) => {
+                        if let Interaction::ApplicationCommand(command) = &interaction.0 {
+                            if let Err(why) = self.handle_interaction(command).await {
+                                tracing::error!("An error occurred whilst processing a command!");
+                                tracing::error!("Interaction: {:?}", command);
+                                tracing::error!("Error: {}", why);
+                            }
                         }
                     }
+                    _ => {}
                 }
             }
-        });
 
-        while let Some(event) = events.next().await {
-            self.cache.update(&event);
+            if self.shutting_down.load(Ordering::SeqCst) {
+                break;
+            }
+
+            tracing::warn!("The gateway event stream ended unexpectedly");
+
+            match self.reconnect().await {
+                Some(new_events) => events = new_events,
+                None => break,
+            }
+        }
+
+        self.cleanup().await;
+
+        Ok(())
+    }
+
+    /// Stops the current shard, the event loop will then exit rather than reconnecting
+    fn shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        self.discord_gateway.read().shutdown();
+    }
+
+    /// Replaces the shard with a new one, backing off exponentially between failed attempts.
+    /// Returns `None` if a shutdown is requested whilst reconnecting
+    async fn reconnect(&self) -> Option<Events> {
+        const INITIAL_DELAY: u64 = 1;
+        const MAX_DELAY: u64 = 60;
+
+        let mut delay = INITIAL_DELAY;
+        let mut attempt = 1;
+
+        loop {
+            tracing::info!(
+                "Reconnecting to the gateway in {} seconds (attempt {})",
+                delay,
+                attempt
+            );
+            sleep(Duration::from_secs(delay)).await;
+
+            if self.shutting_down.load(Ordering::SeqCst) {
+                return None;
+            }
 
-            match event {
-                Event::MessageCreate(message) if !message.author.bot => {
-                    if let Err(why) = self.handle_command(&message).await {
-                        tracing::error!("An error occurred whilst processing a command!");
-                        tracing::error!("Message: {:?}", &message);
-                        tracing::error!("Error: {}", why);
+            let (mut shard, events) = build_shard(&self.token);
+
+            match shard.start().await {
+                Ok(()) => {
+                    tracing::info!("Reconnected to the gateway");
+                    *self.discord_gateway.write() = shard.clone();
+
+                    // A shutdown may have been requested against the old shard whilst starting
+                    if self.shutting_down.load(Ordering::SeqCst) {
+                        shard.shutdown();
                     }
+
+                    return Some(events);
+                }
+                Err(why) => {
+                    tracing::error!("Failed to reconnect to the gateway");
+                    tracing::error!("{}", why);
                 }
-                _ => {}
             }
+
+            delay = (delay * 2).min(MAX_DELAY);
+            attempt += 1;
         }
+    }
 
-        Ok(())
+    /// Unmutes everyone before exiting if a game is in progress, otherwise players would be left
+    /// muted until an admin intervenes
+    async fn cleanup(&self) {
+        let in_game = matches!(*self.game_state_rx.borrow(), Some(State::InGame { .. }));
+
+        if in_game {
+            tracing::info!("Game in progress, restoring players before exiting");
+            self.end_game().await;
+        }
     }
 
     async fn handle_command(&self, message: &Message) -> Result<()> {
-        match self.command_parser.parse(&message.content) {
-            Some(Command {
-                name: "ident",
-                mut arguments,
-                ..
-            }) => self.ident_player(&message, &mut arguments).await?,
-            Some(Command { name: "check", .. }) => self.check_matching(&message).await?,
-            Some(Command { name: "stop", .. }) => {
-                if self.owners.contains(&message.author.id) {
+        if let Some(Command {
+            name,
+            mut arguments,
+            ..
+        }) = self.command_parser.parse(&message.content)
+        {
+            let author = message.author.id;
+
+            let response = match self.command_names.get(name) {
+                Some(BotCommand::Ident) => match arguments.next() {
+                    Some(argument) => match UserId::parse(argument) {
+                        Ok(target) => {
+                            self.ident_player(author, Some(target), arguments.next())
+                                .await
+                        }
+                        Err(_) => self.ident_player(author, None, Some(argument)).await,
+                    },
+                    None => self.ident_player(author, None, None).await,
+                },
+                Some(BotCommand::Help) => self.help()?,
+                Some(BotCommand::Check) => self.check_matching()?,
+                // Non-owners are ignored, rather than told they cannot stop the bot
+                Some(BotCommand::Stop) if !self.owners.contains(&author) => return Ok(()),
+                Some(BotCommand::Stop) => self.stop(author),
+                Some(BotCommand::Mute) => self.override_mute(author, true).await,
+                Some(BotCommand::Unmute) => self.override_mute(author, false).await,
+                None => return Ok(()),
+            };
+
+            match response {
+                Response::Content(content) | Response::Error(content) => {
+                    message.reply(&self.discord_client, content)?.await?;
+                }
+                Response::Embed(embed) => {
                     self.discord_client
                         .create_message(message.channel_id)
-                        .content("Good night")?
-                        .reply(message.id)
+                        .embed(embed)?
                         .await?;
-                    self.discord_gateway.shutdown();
                 }
             }
-            _ => {}
         }
 
         Ok(())
     }
 
-    async fn start_meeting(&self) {
+    async fn handle_interaction(&self, command: &ApplicationCommand) -> Result<()> {
+        let author = match command
+            .member
+            .as_ref()
+            .and_then(|member| member.user.as_ref())
+            .or_else(|| command.user.as_ref())
+        {
+            Some(user) => user.id,
+            None => return Ok(()),
+        };
+
+        let options = &command.data.options;
+
+        let response = match BotCommand::from_key(&command.data.name) {
+            Some(BotCommand::Ident) => {
+                self.ident_player(
+                    author,
+                    user_option(options, "user").filter(|&target| target != author),
+                    string_option(options, "name"),
+                )
+                .await
+            }
+            Some(BotCommand::Help) => self.help()?,
+            Some(BotCommand::Check) => self.check_matching()?,
+            Some(BotCommand::Stop) => self.stop(author),
+            Some(BotCommand::Mute) => {
+                return self.deferred_override_mute(command, author, true).await
+            }
+            Some(BotCommand::Unmute) => {
+                return self.deferred_override_mute(command, author, false).await
+            }
+            None => return Ok(()),
+        };
+
+        self.discord_client
+            .interaction_callback(
+                command.id,
+                &command.token,
+                response.into_interaction_response(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Updating every member in a full lobby can take longer than Discord allows for an
+    /// interaction response, so the response is deferred and the result sent as a follow up
+    async fn deferred_override_mute(
+        &self,
+        command: &ApplicationCommand,
+        author: UserId,
+        mute: bool,
+    ) -> Result<()> {
+        if let Some(refusal) = self.override_refusal(author) {
+            self.discord_client
+                .interaction_callback(
+                    command.id,
+                    &command.token,
+                    refusal.into_interaction_response(),
+                )
+                .await?;
+            return Ok(());
+        }
+
+        self.discord_client
+            .interaction_callback(command.id, &command.token, deferred_response())
+            .await?;
+
+        let follow_up = self
+            .discord_client
+            .create_followup_message(&command.token)?;
+
+        match self.override_mute(author, mute).await {
+            Response::Content(content) | Response::Error(content) => {
+                follow_up.content(content).await?
+            }
+            Response::Embed(embed) => follow_up.embeds(vec![embed]).await?,
+        };
+
+        Ok(())
+    }
+
+    /// Lists the commands, as currently configured, along with the status of the game
+    fn help(&self) -> Result<Response> {
+        let embed = EmbedBuilder::new().title("Taskinator").description(
+            "Players in the living channel are muted during gameplay and unmuted during \
+            meetings. Between meetings, dead players are moved to the dead channel.",
+        );
+
+        let embed = BotCommand::ALL.iter().fold(embed, |embed, &command| {
+            let invocation = if self.text_commands {
+                let name = self
+                    .command_names
+                    .iter()
+                    .find_map(|(name, &c)| {
+                        if c == command {
+                            Some(name.as_str())
+                        } else {
+                            None
+                        }
+                    })
+                    .unwrap_or_else(|| command.key());
+                format!("{}{} {}", self.prefix, name, command.usage())
+            } else {
+                format!("/{}", command.key())
+            };
+
+            embed
+                .field(EmbedFieldBuilder::new(invocation.trim_end(), command.description()).build())
+        });
+
+        let status = match &*self.game_state_rx.borrow() {
+            Some(State::InGame {
+                players, meeting, ..
+            }) => {
+                let dead = players.iter().filter(|p| p.dead).count();
+                format!(
+                    "{}\n{} living, {} dead",
+                    if is_meeting(meeting) {
+                        "In a meeting, living players are unmuted"
+                    } else {
+                        "In game, living players are muted"
+                    },
+                    players.len() - dead,
+                    dead
+                )
+            }
+            Some(State::Lobby { players }) => {
+                format!("In the lobby with {} players", players.len())
+            }
+            Some(State::Menu) => "In the menu".to_owned(),
+            None => "Among Us is not running".to_owned(),
+        };
+
+        let embed = embed.field(EmbedFieldBuilder::new("Game status", status).build());
+
+        Ok(Response::Embed(embed.build()?))
+    }
+
+    fn stop(&self, author: UserId) -> Response {
+        if self.owners.contains(&author) {
+            self.shutdown();
+            Response::Content("Good night".to_owned())
+        } else {
+            Response::error("Only owners can stop the bot")
+        }
+    }
+
+    /// Returns the number of members successfully updated and the number of updates attempted, or
+    /// `None` if the game ended before players could be matched
+    async fn start_meeting(&self) -> Option<(usize, usize)> {
         tracing::info!("Start meeting");
 
         let mut futs = self
-            .match_members_to_players(&self.get_members_in_channel(self.living_channel))
-            .expect("failed to match players at start of meeting - this should not happen!")
+            .match_members_to_players(&self.get_members_in_channel(self.living_channel))?
             .iter()
             .filter_map(|(m, p)| match p {
                 Some(p) if !p.dead => Some(
@@ -352,7 +755,8 @@ impl Bot {
                 }),
         );
 
-        self.batch(futs).await;
+        let attempted = futs.len();
+        Some((self.batch(futs).await.len(), attempted))
     }
 
     async fn end_meeting(&self, bot_state: &mut BotState) {
@@ -385,6 +789,7 @@ impl Bot {
         *bot_state = BotState::InGame;
 
         self.mute_players().await;
+        self.grant_dead_chat().await;
     }
 
     async fn start_game(&self) {
@@ -417,12 +822,141 @@ impl Bot {
         );
 
         self.batch(futs).await;
+
+        self.revoke_dead_chat().await;
+    }
+
+    /// Lets players who died before the meeting that has just ended into dead chat. Members who
+    /// already have an overwrite the bot did not create are left alone
+    async fn grant_dead_chat(&self) {
+        let dead_chat = match self.dead_chat {
+            Some(dead_chat) => dead_chat,
+            None => return,
+        };
+
+        let existing = match self.member_overwrites(dead_chat) {
+            Some(existing) => existing,
+            None => {
+                tracing::warn!("Dead chat is not cached yet, not granting access");
+                return;
+            }
+        };
+
+        // The dead may not have finished moving to the dead channel yet
+        let mut members = self.get_members_in_channel(self.living_channel);
+        members.extend(self.get_members_in_channel(self.dead_channel));
+
+        let newly_dead = {
+            let mut granted = self.dead_chat_members.write();
+
+            let newly_dead = self
+                .match_members_to_players(&members)
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|(m, p)| match p {
+                    Some(p) if p.dead => Some(m.user_id),
+                    _ => None,
+                })
+                .filter(|user_id| !granted.contains(user_id) && !existing.contains(user_id))
+                .collect::<Vec<_>>();
+
+            granted.extend(&newly_dead);
+            newly_dead
+        };
+
+        if newly_dead.is_empty() {
+            return;
+        }
+
+        // Saved before granting, so access is still removed if the bot dies part way through
+        self.save_dead_chat_members().await;
+
+        let futs = newly_dead
+            .iter()
+            .map(|&user_id| {
+                self.discord_client
+                    .update_channel_permission(
+                        dead_chat,
+                        DEAD_CHAT_PERMISSIONS,
+                        Permissions::empty(),
+                    )
+                    .member(user_id)
+            })
+            .collect::<Vec<_>>();
+
+        self.batch(futs).await;
     }
 
-    async fn mute_players(&self) {
+    /// Removes every dead chat overwrite the bot has granted
+    async fn revoke_dead_chat(&self) {
+        let dead_chat = match self.dead_chat {
+            Some(dead_chat) => dead_chat,
+            None => return,
+        };
+
+        let granted = self
+            .dead_chat_members
+            .read()
+            .iter()
+            .copied()
+            .collect::<Vec<_>>();
+
+        if granted.is_empty() {
+            return;
+        }
+
+        let futs = granted
+            .iter()
+            .map(|&user_id| {
+                let revoke = self
+                    .discord_client
+                    .delete_channel_permission(dead_chat)
+                    .member(user_id);
+                async move { revoke.await.map(|_| user_id) }
+            })
+            .collect::<Vec<_>>();
+
+        let revoked = self.batch(futs).await;
+
+        if revoked.is_empty() {
+            return;
+        }
+
+        // Members whose overwrite could not be removed are kept, so the removal is retried the
+        // next time there is no game
+        {
+            let mut members = self.dead_chat_members.write();
+            for user_id in &revoked {
+                members.remove(user_id);
+            }
+        }
+        self.save_dead_chat_members().await;
+    }
+
+    /// Members with their own overwrite on a text channel, or `None` if it is not cached
+    fn member_overwrites(&self, channel: ChannelId) -> Option<HashSet<UserId>> {
+        match self.cache.guild_channel(channel)? {
+            GuildChannel::Text(TextChannel {
+                permission_overwrites,
+                ..
+            }) => Some(
+                permission_overwrites
+                    .iter()
+                    .filter_map(|o| match o.kind {
+                        PermissionOverwriteType::Member(user_id) => Some(user_id),
+                        PermissionOverwriteType::Role(_) => None,
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of members successfully updated and the number of updates attempted, or
+    /// `None` if the game ended before players could be matched
+    async fn mute_players(&self) -> Option<(usize, usize)> {
         let futs = self
-            .match_members_to_players(&self.get_members_in_channel(self.living_channel))
-            .expect("failed to match players at end of meeting - this should not happen!")
+            .match_members_to_players(&self.get_members_in_channel(self.living_channel))?
             .iter()
             .filter_map(|(m, p)| match p {
                 Some(p) if p.dead => Some(
@@ -440,58 +974,85 @@ impl Bot {
             })
             .collect::<Vec<_>>();
 
-        self.batch(futs).await;
+        let attempted = futs.len();
+        Some((self.batch(futs).await.len(), attempted))
     }
 
-    async fn ident_player(&self, message: &Message, arguments: &mut Arguments<'_>) -> Result<()> {
-        match arguments.next() {
-            Some(argument) => {
-                if let Ok(target) = UserId::parse(argument) {
-                    if self.owners.contains(&message.author.id) {
-                        if let Some(ign) = arguments.next() {
-                            self.player_names.write().insert(target, ign.to_owned());
-                            message
-                                .reply(
-                                    &self.discord_client,
-                                    format!("Set {}'s IGN to {}", target.mention(), ign),
-                                )?
-                                .await?;
-                        } else {
-                            message
-                                .reply(&self.discord_client, "You must include an in game name")?
-                                .await?;
-                        }
-                    } else {
-                        message
-                            .reply(
-                                &self.discord_client,
-                                "Only owners can set the in game name of another user",
-                            )?
-                            .await?;
-                    }
-                } else {
-                    self.player_names
-                        .write()
-                        .insert(message.author.id, argument.to_owned());
-                    message
-                        .reply(
-                            &self.discord_client,
-                            format!("Set your in game name to {}", argument),
-                        )?
-                        .await?;
-                }
-            }
-            None => {
-                message
-                    .reply(&self.discord_client, "Please include your in game name")?
-                    .await?;
-            }
+    /// Allows an owner to mute or unmute players without waiting for the game to change state,
+    /// dead players are kept muted in the living channel in the same way as during a meeting
+    async fn override_mute(&self, author: UserId, mute: bool) -> Response {
+        if let Some(refusal) = self.override_refusal(author) {
+            return refusal;
+        }
+
+        let (result, action) = if mute {
+            (self.mute_players().await, "Muted")
+        } else {
+            (self.start_meeting().await, "Unmuted")
         };
 
-        Ok(())
+        // The game can end between the refusal check and matching players
+        let (updated, attempted) = match result {
+            Some(result) => result,
+            None => return Response::error("Must be in a game to mute or unmute players"),
+        };
+
+        if updated == attempted {
+            Response::Content(format!("{} living players", action))
+        } else {
+            Response::Error(format!(
+                "{} living players, but {} of {} updates failed",
+                action,
+                attempted - updated,
+                attempted
+            ))
+        }
     }
 
-    async fn check_matching(&self, message: &Message) -> Result<()> {
+    /// Explains why the author cannot mute or unmute players right now, if they cannot
+    fn override_refusal(&self, author: UserId) -> Option<Response> {
+        if !self.owners.contains(&author) {
+            return Some(Response::error("Only owners can mute or unmute players"));
+        }
+
+        let in_game = matches!(*self.game_state_rx.borrow(), Some(State::InGame { .. }));
+
+        if !in_game {
+            return Some(Response::error(
+                "Must be in a game to mute or unmute players",
+            ));
+        }
+
+        None
+    }
+
+    /// Sets the in game name of `target`, or of the author if no target is given
+    async fn ident_player(
+        &self,
+        author: UserId,
+        target: Option<UserId>,
+        ign: Option<&str>,
+    ) -> Response {
+        match (target, ign) {
+            (Some(_), _) if !self.owners.contains(&author) => {
+                Response::error("Only owners can set the in game name of another user")
+            }
+            (Some(target), Some(ign)) => {
+                self.player_names.write().insert(target, ign.to_owned());
+                self.save_player_names().await;
+                Response::Content(format!("Set {}'s IGN to {}", target.mention(), ign))
+            }
+            (Some(_), None) => Response::error("You must include an in game name"),
+            (None, Some(ign)) => {
+                self.player_names.write().insert(author, ign.to_owned());
+                self.save_player_names().await;
+                Response::Content(format!("Set your in game name to {}", ign))
+            }
+            (None, None) => Response::error("Please include your in game name"),
+        }
+    }
+
+    fn check_matching(&self) -> Result<Response> {
         match self.match_members_to_players(&self.get_members_in_channel(self.living_channel)) {
             Some(matched_players) => {
                 tracing::trace!("{:?}", matched_players);
@@ -501,11 +1062,9 @@ impl Bot {
                     .collect::<Vec<_>>();
 
                 if unmatched_players.is_empty() {
-                    self.discord_client
-                        .create_message(message.channel_id)
-                        .content("All members matched to player")?
-                        .reply(message.id)
-                        .await?;
+                    Ok(Response::Content(
+                        "All members matched to player".to_owned(),
+                    ))
                 } else {
                     let embed = EmbedBuilder::new()
                         .description("Could not match all members to players")
@@ -518,22 +1077,152 @@ impl Bot {
                         )
                     });
 
-                    self.discord_client
-                        .create_message(message.channel_id)
-                        .embed(embed.build()?)?
-                        .await?;
+                    Ok(Response::Embed(embed.build()?))
                 }
             }
-            None => {
-                self.discord_client
-                    .create_message(message.channel_id)
-                    .content("Must be in a lobby to check")?
-                    .reply(message.id)
-                    .await?;
+            None => Ok(Response::error("Must be in a lobby to check")),
+        }
+    }
+
+    /// Writes the in game names set with ident to disk so they survive a restart
+    async fn save_player_names(&self) {
+        let result = save_toml(&self.names_save, &self.names_file, || {
+            self.player_names
+                .read()
+                .iter()
+                .map(|(uid, ign)| (uid.0.to_string(), ign.clone()))
+                .collect::<HashMap<_, _>>()
+        })
+        .await;
+
+        if let Err(why) = result {
+            tracing::warn!("Failed to save in game names: {}", why);
+        }
+    }
+
+    /// Writes the members granted dead chat access to disk, so the access can be removed after a
+    /// restart
+    async fn save_dead_chat_members(&self) {
+        let result = save_toml(&self.dead_chat_save, &self.dead_chat_file, || {
+            DeadChatMembers {
+                members: self
+                    .dead_chat_members
+                    .read()
+                    .iter()
+                    .map(|user_id| user_id.0.to_string())
+                    .collect(),
+            }
+        })
+        .await;
+
+        if let Err(why) = result {
+            tracing::warn!("Failed to save dead chat members: {}", why);
+        }
+    }
+
+    /// Checks the bot can moderate both voice channels before a game starts, rather than failing
+    /// part way through; any missing permissions are reported to the broadcast channel. The
+    /// cache is filled by GUILD_CREATE, which can arrive after the first game state is seen
+    async fn can_moderate(&self) -> Moderation {
+        let required = Permissions::MUTE_MEMBERS | Permissions::MOVE_MEMBERS;
+
+        let mut missing = Permissions::empty();
+        for &channel in &[self.living_channel, self.dead_channel] {
+            match self.channel_permissions(channel) {
+                Some(perms) => missing |= required - perms,
+                None => {
+                    tracing::info!("Guild not cached yet, waiting before moderating the game");
+                    return Moderation::NotReady;
+                }
+            }
+        }
+
+        // Overwrites can only be edited by members with Manage Permissions, and only to grant
+        // permissions the editor has themselves
+        if let Some(dead_chat) = self.dead_chat {
+            match self.channel_permissions(dead_chat) {
+                Some(perms) => {
+                    missing |= (Permissions::MANAGE_ROLES | DEAD_CHAT_PERMISSIONS) - perms
+                }
+                None => {
+                    tracing::info!("Dead chat not cached yet, waiting before moderating the game");
+                    return Moderation::NotReady;
+                }
             }
+        }
+
+        if missing.is_empty() {
+            return Moderation::Allowed;
+        }
+
+        tracing::error!("Cannot moderate game, missing permissions: {:?}", missing);
+
+        let _e_msg = self
+            .discord_client
+            .create_message(self.broadcast_channel)
+            .content(format!(
+                "Cannot moderate this game, the bot is missing the following permissions: {:?}",
+                missing
+            ))
+            .unwrap()
+            .await;
+
+        Moderation::Refused
+    }
+
+    /// Calculates the bot's permissions in a voice or text channel from the cached guild, roles and
+    /// channel overwrites, or `None` if any of these are not cached
+    fn channel_permissions(&self, channel: ChannelId) -> Option<Permissions> {
+        let (guild_id, overwrites) = match self.cache.guild_channel(channel)? {
+            GuildChannel::Voice(VoiceChannel {
+                guild_id: Some(guild_id),
+                permission_overwrites,
+                ..
+            })
+            | GuildChannel::Text(TextChannel {
+                guild_id: Some(guild_id),
+                permission_overwrites,
+                ..
+            }) => (guild_id, permission_overwrites),
+            _ => return None,
         };
 
-        Ok(())
+        let member = self.cache.member(guild_id, self.bot_id)?;
+
+        if self.cache.guild(guild_id)?.owner_id == self.bot_id {
+            return Some(Permissions::all());
+        }
+
+        // The @everyone role shares its ID with the guild
+        let everyone = RoleId(guild_id.0);
+        let base = std::iter::once(&everyone)
+            .chain(member.roles.iter())
+            .filter_map(|&role| self.cache.role(role))
+            .fold(Permissions::empty(), |perms, role| perms | role.permissions);
+
+        if base.contains(Permissions::ADMINISTRATOR) {
+            return Some(Permissions::all());
+        }
+
+        // Overwrites apply @everyone first, then the member's roles, then the member themselves
+        let perms = apply_overwrites(
+            base,
+            overwrites.iter().filter(
+                |o| matches!(o.kind, PermissionOverwriteType::Role(role) if role == everyone),
+            ),
+        );
+        let perms = apply_overwrites(
+            perms,
+            overwrites.iter().filter(|o| {
+                matches!(o.kind, PermissionOverwriteType::Role(role) if member.roles.contains(&role))
+            }),
+        );
+        Some(apply_overwrites(
+            perms,
+            overwrites.iter().filter(
+                |o| matches!(o.kind, PermissionOverwriteType::Member(user) if user == self.bot_id),
+            ),
+        ))
     }
 
     fn match_members_to_players(
@@ -590,17 +1279,8 @@ impl Bot {
     async fn batch<Fut, Out>(&self, futs: Vec<Fut>) -> Vec<Out>
     where
         Fut: Future<Output = TwiResult<Out>>,
-        Out: Debug,
     {
-        let (successes, errors) = futures::future::join_all(futs)
-            .await
-            .into_iter()
-            .partition::<Vec<_>, _>(TwiResult::is_ok);
-
-        let errors = errors
-            .into_iter()
-            .map(TwiResult::unwrap_err)
-            .collect::<Vec<_>>();
+        let (successes, errors) = join_partitioned(futs).await;
 
         if !errors.is_empty() {
             let _e_msg = self
@@ -614,6 +1294,205 @@ impl Bot {
             }
         }
 
-        successes.into_iter().map(TwiResult::unwrap).collect()
+        successes
+    }
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct DeadChatMembers {
+    members: Vec<String>,
+}
+
+fn load_player_names(path: &Path) -> HashMap<UserId, String> {
+    load_toml::<HashMap<String, String>>(path, "in game names")
+        .into_iter()
+        .filter_map(|(uid, ign)| uid.parse().ok().map(|uid| (UserId(uid), ign)))
+        .collect()
+}
+
+fn load_toml<T: DeserializeOwned + Default>(path: &Path, description: &str) -> T {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(why) if why.kind() == ErrorKind::NotFound => return T::default(),
+        Err(why) => {
+            tracing::warn!("Failed to read saved {}: {}", description, why);
+            return T::default();
+        }
+    };
+
+    match toml::from_str(&contents) {
+        Ok(value) => value,
+        Err(why) => {
+            tracing::warn!("Failed to parse saved {}: {}", description, why);
+            T::default()
+        }
+    }
+}
+
+/// Writes the value taken by `snapshot` to `path`, holding `lock` from before the snapshot is
+/// taken until the write completes so that an older snapshot can never overwrite a newer one
+async fn save_toml<T: Serialize>(
+    lock: &Mutex<()>,
+    path: &Path,
+    snapshot: impl FnOnce() -> T,
+) -> Result<()> {
+    let _save = lock.lock().await;
+
+    let contents = toml::to_string(&snapshot())?;
+
+    let path = path.to_owned();
+    task::spawn_blocking(move || write_atomically(&path, contents.as_bytes())).await??;
+
+    Ok(())
+}
+
+/// Permissions are only checked when a game is first seen, which includes a game already in
+/// progress when the bot starts
+fn needs_moderation_check(bot_state: BotState, phase: Phase) -> bool {
+    matches!(bot_state, BotState::PreGame) && !matches!(phase, Phase::NoGame)
+}
+
+fn transition(
+    bot_state: BotState,
+    phase: Phase,
+    moderation: Moderation,
+) -> (BotState, Option<Action>) {
+    match (bot_state, phase) {
+        (BotState::PreGame, Phase::Gameplay | Phase::Meeting) => match (moderation, phase) {
+            (Moderation::Allowed, Phase::Meeting) => {
+                (BotState::InMeeting, Some(Action::StartMeeting))
+            }
+            (Moderation::Allowed, _) => (BotState::InGame, Some(Action::StartGame)),
+            (Moderation::Refused, _) => (BotState::GameOver, None),
+            // Left in PreGame so that the check is tried again
+            (Moderation::NotReady, _) => (BotState::PreGame, None),
+        },
+        (BotState::InGame, Phase::Meeting) => (BotState::InMeeting, Some(Action::StartMeeting)),
+        (BotState::InMeeting, Phase::Gameplay) => (BotState::InMeeting, Some(Action::EndMeeting)),
+        (BotState::InGame | BotState::InMeeting, Phase::NoGame) => {
+            (BotState::PreGame, Some(Action::EndGame))
+        }
+        (_, Phase::NoGame) => (BotState::PreGame, None),
+        (bot_state, _) => (bot_state, None),
+    }
+}
+
+fn is_meeting(meeting: &MeetingState) -> bool {
+    matches!(
+        meeting,
+        MeetingState::Discussion
+            | MeetingState::NotVoted
+            | MeetingState::Voted
+            | MeetingState::Results
+    )
+}
+
+fn apply_overwrites<'a>(
+    perms: Permissions,
+    overwrites: impl Iterator<Item = &'a PermissionOverwrite>,
+) -> Permissions {
+    let (allow, deny) = overwrites.fold(
+        (Permissions::empty(), Permissions::empty()),
+        |(allow, deny), o| (allow | o.allow, deny | o.deny),
+    );
+
+    (perms - deny) | allow
+}
+
+fn build_shard(token: &str) -> (Shard, Events) {
+    let event_flags: EventTypeFlags = EventTypeFlags::CHANNEL_UPDATE
+        | EventTypeFlags::GUILD_CREATE
+        | EventTypeFlags::INTERACTION_CREATE
+        | EventTypeFlags::MEMBER_ADD
+        | EventTypeFlags::MEMBER_UPDATE
+        | EventTypeFlags::MESSAGE_CREATE
+        | EventTypeFlags::VOICE_STATE_UPDATE;
+
+    Shard::builder(
+        token,
+        Intents::GUILDS
+            | Intents::GUILD_MEMBERS
+            | Intents::GUILD_MESSAGES
+            | Intents::GUILD_VOICE_STATES,
+    )
+    .event_types(event_flags)
+    .build()
+}
+
+/// Completes when the process is asked to exit, either by ctrl+c or, on unix, SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    result = ctrl_c() => {
+                        if let Err(why) = result {
+                            tracing::error!("There was an error registering the ctrl+c handler");
+                            tracing::error!("{}", why);
+                        }
+                    }
+                    _ = sigterm.recv() => tracing::info!("Received SIGTERM"),
+                }
+                return;
+            }
+            Err(why) => {
+                tracing::error!("There was an error registering the SIGTERM handler");
+                tracing::error!("{}", why);
+            }
+        }
+    }
+
+    if let Err(why) = ctrl_c().await {
+        tracing::error!("There was an error registering the ctrl+c handler");
+        tracing::error!("{}", why);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{transition, Action, BotState, Moderation, Phase};
+
+    #[test]
+    fn restart_mid_game_mutes_players_once_cache_is_ready() {
+        // After a restart the bot is back in PreGame whilst the game carries on
+        assert_eq!(
+            transition(BotState::PreGame, Phase::Gameplay, Moderation::NotReady),
+            (BotState::PreGame, None)
+        );
+        assert_eq!(
+            transition(BotState::PreGame, Phase::Gameplay, Moderation::Allowed),
+            (BotState::InGame, Some(Action::StartGame))
+        );
+    }
+
+    #[test]
+    fn restart_mid_meeting_unmutes_players_once_cache_is_ready() {
+        assert_eq!(
+            transition(BotState::PreGame, Phase::Meeting, Moderation::NotReady),
+            (BotState::PreGame, None)
+        );
+        assert_eq!(
+            transition(BotState::PreGame, Phase::Meeting, Moderation::Allowed),
+            (BotState::InMeeting, Some(Action::StartMeeting))
+        );
+    }
+
+    #[test]
+    fn refused_game_is_left_alone_until_the_lobby() {
+        assert_eq!(
+            transition(BotState::PreGame, Phase::Gameplay, Moderation::Refused),
+            (BotState::GameOver, None)
+        );
+        assert_eq!(
+            transition(BotState::GameOver, Phase::Meeting, Moderation::Allowed),
+            (BotState::GameOver, None)
+        );
+        assert_eq!(
+            transition(BotState::GameOver, Phase::NoGame, Moderation::Allowed),
+            (BotState::PreGame, None)
+        );
     }
 }
@@ -0,0 +1,163 @@
+use twilight_model::{
+    application::{
+        callback::{CallbackData, InteractionResponse},
+        command::{BaseCommandOptionData, ChoiceCommandOptionData, Command, CommandOption},
+        interaction::application_command::CommandDataOption,
+    },
+    channel::{embed::Embed, message::MessageFlags},
+    id::UserId,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BotCommand {
+    Help,
+    Ident,
+    Check,
+    Stop,
+    Mute,
+    Unmute,
+}
+
+impl BotCommand {
+    pub const ALL: [BotCommand; 6] = [
+        BotCommand::Help,
+        BotCommand::Ident,
+        BotCommand::Check,
+        BotCommand::Stop,
+        BotCommand::Mute,
+        BotCommand::Unmute,
+    ];
+
+    /// The key used to refer to this command in the config file, also used as its default name
+    /// and as the name of its slash command
+    pub fn key(self) -> &'static str {
+        match self {
+            BotCommand::Help => "help",
+            BotCommand::Ident => "ident",
+            BotCommand::Check => "check",
+            BotCommand::Stop => "stop",
+            BotCommand::Mute => "mute",
+            BotCommand::Unmute => "unmute",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|command| command.key() == key)
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            BotCommand::Help => "List the commands and the status of the current game",
+            BotCommand::Ident => "Set the in game name the bot uses to match you to a player",
+            BotCommand::Check => "Check that every member in the voice channel matches a player",
+            BotCommand::Stop => "Stop the bot (owners only)",
+            BotCommand::Mute => "Mute the living players now (owners only)",
+            BotCommand::Unmute => "Unmute the living players for discussion (owners only)",
+        }
+    }
+
+    /// The arguments taken by the text version of this command
+    pub fn usage(self) -> &'static str {
+        match self {
+            BotCommand::Ident => "[@user] <IN_GAME_NAME>",
+            _ => "",
+        }
+    }
+
+    fn options(self) -> Vec<CommandOption> {
+        match self {
+            BotCommand::Ident => vec![
+                CommandOption::String(ChoiceCommandOptionData {
+                    choices: Vec::new(),
+                    description: "Your name in Among Us".to_owned(),
+                    name: "name".to_owned(),
+                    required: true,
+                }),
+                CommandOption::User(BaseCommandOptionData {
+                    description: "Set the name of another member instead (owners only)".to_owned(),
+                    name: "user".to_owned(),
+                    required: false,
+                }),
+            ],
+            _ => Vec::new(),
+        }
+    }
+
+    /// The slash commands to register on startup, one per bot command
+    pub fn slash_commands() -> Vec<Command> {
+        Self::ALL
+            .iter()
+            .map(|&command| Command {
+                application_id: None,
+                guild_id: None,
+                name: command.key().to_owned(),
+                default_permission: None,
+                description: command.description().to_owned(),
+                id: None,
+                options: command.options(),
+            })
+            .collect()
+    }
+}
+
+/// The outcome of a command, sent either as a reply to a message or as an interaction response
+pub enum Response {
+    Content(String),
+    Embed(Embed),
+    /// Only shown to the person who used the command when responding to an interaction
+    Error(String),
+}
+
+impl Response {
+    pub fn error(content: impl Into<String>) -> Self {
+        Response::Error(content.into())
+    }
+
+    pub fn into_interaction_response(self) -> InteractionResponse {
+        let (content, embeds, flags) = match self {
+            Response::Content(content) => (Some(content), Vec::new(), None),
+            Response::Embed(embed) => (None, vec![embed], None),
+            Response::Error(content) => (Some(content), Vec::new(), Some(MessageFlags::EPHEMERAL)),
+        };
+
+        InteractionResponse::ChannelMessageWithSource(CallbackData {
+            allowed_mentions: None,
+            content,
+            embeds,
+            flags,
+            tts: None,
+        })
+    }
+}
+
+/// Acknowledges an interaction so the result can be sent as a follow up, for commands which may
+/// not finish within the time Discord allows for a response
+pub fn deferred_response() -> InteractionResponse {
+    InteractionResponse::DeferredChannelMessageWithSource(CallbackData {
+        allowed_mentions: None,
+        content: None,
+        embeds: Vec::new(),
+        flags: None,
+        tts: None,
+    })
+}
+
+pub fn string_option<'a>(options: &'a [CommandDataOption], name: &str) -> Option<&'a str> {
+    options.iter().find_map(|option| match option {
+        CommandDataOption::String {
+            name: option_name,
+            value,
+        } if option_name == name => Some(value.as_str()),
+        _ => None,
+    })
+}
+
+/// User options are sent as the user's ID
+pub fn user_option(options: &[CommandDataOption], name: &str) -> Option<UserId> {
+    string_option(options, name)
+        .and_then(|id| id.parse().ok())
+        .map(UserId)
+}
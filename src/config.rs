@@ -1,8 +1,13 @@
 use crate::Result;
 
-use std::{fs::File, io::Read, path::Path};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
 
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use twilight_model::id::ChannelId;
 
 #[derive(Deserialize)]
@@ -11,6 +16,21 @@ pub struct Config {
     pub broadcast_channel: ChannelId,
     pub living_channel: ChannelId,
     pub dead_channel: ChannelId,
+    pub dead_chat: Option<ChannelId>,
+    #[serde(default = "default_dead_chat_file")]
+    pub dead_chat_file: PathBuf,
+    #[serde(
+        rename = "prefix",
+        default = "default_prefixes",
+        deserialize_with = "one_or_many"
+    )]
+    pub prefixes: Vec<String>,
+    #[serde(default)]
+    pub commands: HashMap<String, String>,
+    #[serde(default = "default_names_file")]
+    pub names_file: PathBuf,
+    #[serde(default = "default_text_commands")]
+    pub text_commands: bool,
 }
 
 impl Config {
@@ -23,6 +43,52 @@ impl Config {
 
         let config: Config = toml::from_str(&config_str)?;
 
+        if config.prefixes.is_empty() {
+            return Err("At least one command prefix must be configured".into());
+        }
+
+        if config.prefixes.iter().any(String::is_empty) {
+            return Err("Command prefixes must not be empty".into());
+        }
+
+        if let Some((command, _)) = config.commands.iter().find(|(_, name)| name.is_empty()) {
+            return Err(format!("The name for the {} command must not be empty", command).into());
+        }
+
         Ok(config)
     }
 }
+
+fn default_prefixes() -> Vec<String> {
+    vec!["~".to_owned()]
+}
+
+fn default_names_file() -> PathBuf {
+    PathBuf::from("./Names.toml")
+}
+
+fn default_dead_chat_file() -> PathBuf {
+    PathBuf::from("./DeadChat.toml")
+}
+
+fn default_text_commands() -> bool {
+    true
+}
+
+/// Allows `prefix` to be given as either a single string or a list of strings
+fn one_or_many<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(prefix) => vec![prefix],
+        OneOrMany::Many(prefixes) => prefixes,
+    })
+}
@@ -8,6 +8,7 @@
 )]
 
 mod bot;
+mod commands;
 mod config;
 mod utils;
 
@@ -130,7 +131,7 @@ async fn bot_main() -> Result<()> {
 
     // Setup bot
     tracing::info!("Constructing bot instance from config");
-    let (mut bot, events) = Bot::builder("./Config.toml").build(rx).await?;
+    let (bot, events) = Bot::builder("./Config.toml").build(rx).await?;
 
     bot.start(events).await?;
 
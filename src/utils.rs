@@ -1,3 +1,6 @@
+use std::{fs, future::Future, io, path::Path};
+
+use futures::future::join_all;
 use twilight_cache_inmemory::model::CachedMember;
 use twilight_http::{request::prelude::CreateMessage, Client};
 use twilight_model::{channel::Message, user::User};
@@ -37,3 +40,67 @@ impl ReplyTo for Message {
             .content(content)?)
     }
 }
+
+/// Writes to a temporary file alongside `path` and then renames it into place, so a crash part
+/// way through a write cannot leave `path` truncated
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+
+    fs::write(&temp_path, contents)?;
+    fs::rename(&temp_path, path)
+}
+
+/// Awaits every future concurrently, splitting the results into successes and errors; a failure
+/// never prevents the remaining futures from being awaited
+pub async fn join_partitioned<Fut, Out, E>(futs: Vec<Fut>) -> (Vec<Out>, Vec<E>)
+where
+    Fut: Future<Output = std::result::Result<Out, E>>,
+{
+    let mut successes = Vec::new();
+    let mut errors = Vec::new();
+
+    for result in join_all(futs).await {
+        match result {
+            Ok(out) => successes.push(out),
+            Err(why) => errors.push(why),
+        }
+    }
+
+    (successes, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::join_partitioned;
+
+    #[tokio::test]
+    async fn join_partitioned_awaits_every_future_despite_errors() {
+        const MEMBERS: usize = 15;
+        const FAILING_MEMBER: usize = 0;
+
+        let awaited = AtomicUsize::new(0);
+
+        let futs = (0..MEMBERS)
+            .map(|member| {
+                let awaited = &awaited;
+                async move {
+                    awaited.fetch_add(1, Ordering::SeqCst);
+                    if member == FAILING_MEMBER {
+                        Err(member)
+                    } else {
+                        Ok(member)
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let (successes, errors) = join_partitioned(futs).await;
+
+        assert_eq!(awaited.load(Ordering::SeqCst), MEMBERS);
+        assert_eq!(successes.len(), MEMBERS - 1);
+        assert_eq!(errors, vec![FAILING_MEMBER]);
+    }
+}